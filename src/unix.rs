@@ -13,33 +13,39 @@ use libgssapi::{
     oid::{OidSet, GSS_MECH_KRB5, GSS_NT_KRB5_PRINCIPAL},
     util::Buf,
 };
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
 #[cfg(feature = "krb5_iov")]
 fn wrap_iov(
     ctx: &impl SecurityContext,
     encrypt: bool,
     header: &mut BytesMut,
+    sign: &mut [BytesMut],
     data: &mut BytesMut,
     padding: &mut BytesMut,
     trailer: &mut BytesMut,
 ) -> Result<()> {
-    let mut len_iovs = [
-        GssIovFake::new(GssIovType::Header),
-        GssIov::new(GssIovType::Data, &mut **data).as_fake(),
-        GssIovFake::new(GssIovType::Padding),
-        GssIovFake::new(GssIovType::Trailer),
-    ];
+    let mut len_iovs = Vec::with_capacity(4 + sign.len());
+    len_iovs.push(GssIovFake::new(GssIovType::Header));
+    for _ in sign.iter() {
+        len_iovs.push(GssIovFake::new(GssIovType::SignOnly));
+    }
+    len_iovs.push(GssIov::new(GssIovType::Data, &mut **data).as_fake());
+    len_iovs.push(GssIovFake::new(GssIovType::Padding));
+    len_iovs.push(GssIovFake::new(GssIovType::Trailer));
     ctx.wrap_iov_length(encrypt, &mut len_iovs[..])?;
+    let n = len_iovs.len();
     header.resize(len_iovs[0].len(), 0x0);
-    padding.resize(len_iovs[2].len(), 0x0);
-    trailer.resize(len_iovs[3].len(), 0x0);
-    let mut iovs = [
-        GssIov::new(GssIovType::Header, &mut **header),
-        GssIov::new(GssIovType::Data, &mut **data),
-        GssIov::new(GssIovType::Padding, &mut **padding),
-        GssIov::new(GssIovType::Trailer, &mut **trailer),
-    ];
+    padding.resize(len_iovs[n - 2].len(), 0x0);
+    trailer.resize(len_iovs[n - 1].len(), 0x0);
+    let mut iovs = Vec::with_capacity(4 + sign.len());
+    iovs.push(GssIov::new(GssIovType::Header, &mut **header));
+    for s in sign.iter_mut() {
+        iovs.push(GssIov::new(GssIovType::SignOnly, &mut **s));
+    }
+    iovs.push(GssIov::new(GssIovType::Data, &mut **data));
+    iovs.push(GssIov::new(GssIovType::Padding, &mut **padding));
+    iovs.push(GssIov::new(GssIovType::Trailer, &mut **trailer));
     Ok(ctx.wrap_iov(encrypt, &mut iovs)?)
 }
 
@@ -48,25 +54,39 @@ fn wrap_iov(
     ctx: &impl SecurityContext,
     encrypt: bool,
     _header: &mut BytesMut,
+    sign: &mut [BytesMut],
     data: &mut BytesMut,
     _padding: &mut BytesMut,
     _trailer: &mut BytesMut,
 ) -> Result<()> {
+    if !sign.is_empty() {
+        return Err(Error::msg(
+            "sign-only (associated data) buffers require the krb5_iov feature",
+        ));
+    }
     let token = ctx.wrap(encrypt, &**data)?;
     data.clear();
     Ok(data.extend_from_slice(&*token))
 }
 
 #[cfg(feature = "krb5_iov")]
-fn unwrap_iov(ctx: &impl SecurityContext, len: usize, msg: &mut BytesMut) -> Result<BytesMut> {
+fn unwrap_iov(
+    ctx: &impl SecurityContext,
+    len: usize,
+    sign: &mut [BytesMut],
+    msg: &mut BytesMut,
+) -> Result<BytesMut> {
     let (hdr_len, data_len) = {
-        let mut iov = [
-            GssIov::new(GssIovType::Stream, &mut msg[0..len]),
-            GssIov::new(GssIovType::Data, &mut []),
-        ];
+        let mut iov = Vec::with_capacity(2 + sign.len());
+        iov.push(GssIov::new(GssIovType::Stream, &mut msg[0..len]));
+        for s in sign.iter_mut() {
+            iov.push(GssIov::new(GssIovType::SignOnly, &mut **s));
+        }
+        iov.push(GssIov::new(GssIovType::Data, &mut []));
         ctx.unwrap_iov(&mut iov[..])?;
-        let hdr_len = iov[0].header_length(&iov[1]).unwrap();
-        let data_len = iov[1].len();
+        let data_idx = iov.len() - 1;
+        let hdr_len = iov[0].header_length(&iov[data_idx]).unwrap();
+        let data_len = iov[data_idx].len();
         (hdr_len, data_len)
     };
     msg.advance(hdr_len);
@@ -76,7 +96,17 @@ fn unwrap_iov(ctx: &impl SecurityContext, len: usize, msg: &mut BytesMut) -> Res
 }
 
 #[cfg(not(feature = "krb5_iov"))]
-fn unwrap_iov(ctx: &impl SecurityContext, len: usize, msg: &mut BytesMut) -> Result<BytesMut> {
+fn unwrap_iov(
+    ctx: &impl SecurityContext,
+    len: usize,
+    sign: &mut [BytesMut],
+    msg: &mut BytesMut,
+) -> Result<BytesMut> {
+    if !sign.is_empty() {
+        return Err(Error::msg(
+            "sign-only (associated data) buffers require the krb5_iov feature",
+        ));
+    }
     let mut msg = msg.split_to(len);
     let decrypted = ctx.unwrap(&*msg)?;
     msg.clear();
@@ -84,11 +114,75 @@ fn unwrap_iov(ctx: &impl SecurityContext, len: usize, msg: &mut BytesMut) -> Res
     Ok(msg)
 }
 
+// A nominal plaintext length used only to measure the fixed per-message
+// overhead (header + padding + trailer). CFX's overhead doesn't depend on
+// the data length, so any value works; it must just be nonzero.
+const NOMINAL_WRAP_LEN: usize = 128;
+
+#[cfg(feature = "krb5_iov")]
+fn max_wrap_size(
+    ctx: &impl SecurityContext,
+    encrypt: bool,
+    max_output_size: usize,
+) -> Result<usize> {
+    let mut data = BytesMut::new();
+    data.resize(NOMINAL_WRAP_LEN, 0x0);
+    let mut len_iovs = [
+        GssIovFake::new(GssIovType::Header),
+        GssIov::new(GssIovType::Data, &mut data[..]).as_fake(),
+        GssIovFake::new(GssIovType::Padding),
+        GssIovFake::new(GssIovType::Trailer),
+    ];
+    ctx.wrap_iov_length(encrypt, &mut len_iovs[..])?;
+    let overhead = len_iovs[0].len() + len_iovs[2].len() + len_iovs[3].len();
+    Ok(max_output_size.saturating_sub(overhead))
+}
+
+#[cfg(not(feature = "krb5_iov"))]
+fn max_wrap_size(
+    ctx: &impl SecurityContext,
+    encrypt: bool,
+    max_output_size: usize,
+) -> Result<usize> {
+    let token = ctx.wrap(encrypt, &[0u8; NOMINAL_WRAP_LEN])?;
+    let overhead = token.len().saturating_sub(NOMINAL_WRAP_LEN);
+    Ok(max_output_size.saturating_sub(overhead))
+}
+
+/// Returned (wrapped in `anyhow::Error`) by `verify_mic` when the token's
+/// signature doesn't match the message, so callers can `downcast_ref` it to
+/// tell a tampered/forged message apart from an ordinary transport failure.
+#[derive(Debug)]
+pub struct BadMic;
+
+impl fmt::Display for BadMic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mic verification failed, message is not authentic")
+    }
+}
+
+impl std::error::Error for BadMic {}
+
+fn verify_mic(ctx: &impl SecurityContext, msg: &[u8], token: &[u8]) -> Result<()> {
+    ctx.verify_mic(msg, token).map_err(|e| {
+        if e.major.contains(MajorFlags::GSS_S_BAD_SIG) {
+            Error::new(BadMic)
+        } else {
+            Error::from(e)
+        }
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct ClientCtx(GssClientCtx);
 
 impl ClientCtx {
-    pub fn new(principal: Option<&str>, target_principal: &str) -> Result<Self> {
+    /// `flags` controls what the initiator asks the mechanism for, e.g.
+    /// `CtxFlags::GSS_C_MUTUAL_FLAG` for today's default behavior, or that
+    /// combined with `CtxFlags::GSS_C_DELEG_FLAG` to request a forwardable
+    /// ticket (see `ServerCtx::delegated_cred`). Use `flags()` on the
+    /// established context to see what the mechanism actually granted.
+    pub fn new(principal: Option<&str>, target_principal: &str, flags: CtxFlags) -> Result<Self> {
         let name = principal
             .map(|n| {
                 Name::new(n.as_bytes(), Some(&GSS_NT_KRB5_PRINCIPAL))?
@@ -105,12 +199,37 @@ impl ClientCtx {
         Ok(ClientCtx(GssClientCtx::new(
             cred,
             target,
-            CtxFlags::GSS_C_MUTUAL_FLAG,
+            flags,
+            Some(&GSS_MECH_KRB5),
+        )))
+    }
+
+    /// Build a `ClientCtx` from a credential delegated to us by another
+    /// principal (see `ServerCtx::delegated_cred`), so we can initiate a new
+    /// context on that principal's behalf.
+    pub fn new_with_cred(
+        cred: DelegatedCred,
+        target_principal: &str,
+        flags: CtxFlags,
+    ) -> Result<Self> {
+        let target = Name::new(target_principal.as_bytes(), Some(&GSS_NT_KRB5_PRINCIPAL))?
+            .canonicalize(Some(&GSS_MECH_KRB5))?;
+        Ok(ClientCtx(GssClientCtx::new(
+            cred.0,
+            target,
+            flags,
             Some(&GSS_MECH_KRB5),
         )))
     }
 }
 
+/// A credential delegated to us by an initiator that set `GSS_C_DELEG_FLAG`
+/// (see `ServerCtx::delegated_cred`). It carries the initiator's forwarded
+/// TGT, so treat it as secret, and pass it to `ClientCtx::new_with_cred` to
+/// initiate a context on the delegating principal's behalf.
+#[derive(Debug)]
+pub struct DelegatedCred(Cred);
+
 impl K5Ctx for ClientCtx {
     type Buf = Buf;
 
@@ -126,30 +245,58 @@ impl K5Ctx for ClientCtx {
         &self,
         encrypt: bool,
         header: &mut BytesMut,
+        sign: &mut [BytesMut],
         data: &mut BytesMut,
         padding: &mut BytesMut,
         trailer: &mut BytesMut,
     ) -> Result<()> {
-        wrap_iov(&self.0, encrypt, header, data, padding, trailer)
+        wrap_iov(&self.0, encrypt, header, sign, data, padding, trailer)
     }
 
     fn unwrap(&self, msg: &[u8]) -> Result<Self::Buf> {
         self.0.unwrap(msg).map_err(|e| Error::from(e))
     }
 
-    fn unwrap_iov(&self, len: usize, msg: &mut BytesMut) -> Result<BytesMut> {
-        unwrap_iov(&self.0, len, msg)
+    fn unwrap_iov(
+        &self,
+        len: usize,
+        sign: &mut [BytesMut],
+        msg: &mut BytesMut,
+    ) -> Result<BytesMut> {
+        unwrap_iov(&self.0, len, sign, msg)
     }
 
     fn ttl(&self) -> Result<Duration> {
         self.0.lifetime().map_err(|e| Error::from(e))
     }
+
+    fn max_wrap_size(&self, encrypt: bool, max_output_size: usize) -> Result<usize> {
+        max_wrap_size(&self.0, encrypt, max_output_size)
+    }
+
+    fn get_mic(&self, msg: &[u8]) -> Result<Self::Buf> {
+        self.0.get_mic(msg).map_err(|e| Error::from(e))
+    }
+
+    fn verify_mic(&self, msg: &[u8], token: &[u8]) -> Result<()> {
+        verify_mic(&self.0, msg, token)
+    }
+
+    fn flags(&self) -> Result<CtxFlags> {
+        self.0.flags().map_err(|e| Error::from(e))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerCtx(GssServerCtx);
 
 impl ServerCtx {
+    /// Unlike `ClientCtx::new`, this takes no `flags` parameter:
+    /// `gss_accept_sec_context` has no requested-flags input, only an
+    /// output `ret_flags` describing what the mechanism granted. Inspect
+    /// that with `flags()` on the established context instead (e.g. to
+    /// detect an anonymous initiator or reject a context that lacks
+    /// sequencing/replay detection).
     pub fn new(principal: Option<&str>) -> Result<ServerCtx> {
         let name = principal
             .map(|principal| -> Result<Name> {
@@ -166,6 +313,15 @@ impl ServerCtx {
         };
         Ok(ServerCtx(GssServerCtx::new(cred)))
     }
+
+    /// Take the credential delegated by the initiator, if it set
+    /// `GSS_C_DELEG_FLAG` and the context has finished establishing. Pass the
+    /// result to `ClientCtx::new_with_cred` to initiate onward contexts as
+    /// the delegating principal. Takes `&mut self` because the underlying
+    /// GSS credential handle is moved out of the context, not borrowed.
+    pub fn delegated_cred(&mut self) -> Result<Option<DelegatedCred>> {
+        Ok(self.0.take_delegated_cred().map(DelegatedCred))
+    }
 }
 
 impl K5Ctx for ServerCtx {
@@ -190,24 +346,46 @@ impl K5Ctx for ServerCtx {
         &self,
         encrypt: bool,
         header: &mut BytesMut,
+        sign: &mut [BytesMut],
         data: &mut BytesMut,
         padding: &mut BytesMut,
         trailer: &mut BytesMut,
     ) -> Result<()> {
-        wrap_iov(&self.0, encrypt, header, data, padding, trailer)
+        wrap_iov(&self.0, encrypt, header, sign, data, padding, trailer)
     }
 
     fn unwrap(&self, msg: &[u8]) -> Result<Self::Buf> {
         self.0.unwrap(msg).map_err(|e| Error::from(e))
     }
 
-    fn unwrap_iov(&self, len: usize, msg: &mut BytesMut) -> Result<BytesMut> {
-        unwrap_iov(&self.0, len, msg)
+    fn unwrap_iov(
+        &self,
+        len: usize,
+        sign: &mut [BytesMut],
+        msg: &mut BytesMut,
+    ) -> Result<BytesMut> {
+        unwrap_iov(&self.0, len, sign, msg)
     }
 
     fn ttl(&self) -> Result<Duration> {
         self.0.lifetime().map_err(|e| Error::from(e))
     }
+
+    fn max_wrap_size(&self, encrypt: bool, max_output_size: usize) -> Result<usize> {
+        max_wrap_size(&self.0, encrypt, max_output_size)
+    }
+
+    fn get_mic(&self, msg: &[u8]) -> Result<Self::Buf> {
+        self.0.get_mic(msg).map_err(|e| Error::from(e))
+    }
+
+    fn verify_mic(&self, msg: &[u8], token: &[u8]) -> Result<()> {
+        verify_mic(&self.0, msg, token)
+    }
+
+    fn flags(&self) -> Result<CtxFlags> {
+        self.0.flags().map_err(|e| Error::from(e))
+    }
 }
 
 impl K5ServerCtx for ServerCtx {